@@ -6,17 +6,23 @@
  */
 
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::num::NonZeroUsize;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
 use anyhow::Result;
 use async_trait::async_trait;
 use dag::DagAlgorithm;
 use itertools::Itertools;
+use lru::LruCache;
 use manifest::DiffType;
 use manifest::Manifest;
 use manifest_tree::Diff;
 use manifest_tree::TreeManifest;
 use manifest_tree::TreeStore;
+use parking_lot::Mutex;
 use pathhistory::RenameTracer;
 use pathmatcher::AlwaysMatcher;
 use storemodel::futures::StreamExt;
@@ -29,6 +35,71 @@ use types::RepoPathBuf;
 use crate::error::CopyTraceError;
 use crate::CopyTrace;
 
+/// Below this similarity score (fraction of lines shared between the old and
+/// new content), a candidate rename/copy pair is not considered a match.
+const DEFAULT_SIMILARITY_THRESHOLD: f32 = 0.5;
+
+/// Upper bound on the number of (addition, deletion) candidate pairs we're
+/// willing to content-compare in a single `find_renames` call. Content
+/// similarity is O(deletions * additions), so a huge refactor commit could
+/// otherwise make this pathologically slow.
+const DEFAULT_MAX_RENAME_CANDIDATES: usize = 2000;
+
+/// Files larger than this are compared by blob id only; their content is
+/// never read for line-similarity scoring.
+const DEFAULT_MAX_FILE_BYTES: u64 = 1024 * 1024;
+
+/// Default number of entries kept in the tree-manifest and rename-map
+/// caches (see `DagCopyTrace`'s `manifest_cache`/`rename_cache` fields).
+const DEFAULT_CACHE_SIZE: usize = 1000;
+
+/// Tunables for rename/copy detection, analogous to git's
+/// `diff.renameLimit`/`diff.renames`.
+#[derive(Debug, Clone)]
+pub struct CopyTraceConfig {
+    /// Minimum line-similarity score (in `[0, 1]`) for a candidate pair to
+    /// be considered a rename/copy.
+    pub similarity_threshold: f32,
+
+    /// Max number of (addition, deletion-or-survivor) candidate pairs to
+    /// content-compare per `find_renames`/`find_copies` call. Once the
+    /// candidate product exceeds this, the engine falls back to exact
+    /// blob-id matching only and logs a warning instead of doing unbounded
+    /// work on a huge commit.
+    pub max_rename_candidates: usize,
+
+    /// Files larger than this are compared by blob id only and never
+    /// line-diffed.
+    pub max_file_bytes: u64,
+
+    /// Whether to run the content-similarity fallback at all. When `false`,
+    /// only exact blob-id matches (and rename metadata) are used.
+    pub enable_content_similarity: bool,
+
+    /// Whether to additionally detect copies (source survives in the
+    /// destination tree) via `find_copies`/`trace_copy`.
+    pub enable_copies: bool,
+
+    /// Number of entries kept in the tree-manifest cache and in the
+    /// per-commit-pair rename-map cache (see `DagCopyTrace`). A single
+    /// `trace_rename` call over a long history can otherwise re-resolve the
+    /// same vertex or the same adjacent-commit rename set many times.
+    pub cache_size: usize,
+}
+
+impl Default for CopyTraceConfig {
+    fn default() -> Self {
+        Self {
+            similarity_threshold: DEFAULT_SIMILARITY_THRESHOLD,
+            max_rename_candidates: DEFAULT_MAX_RENAME_CANDIDATES,
+            max_file_bytes: DEFAULT_MAX_FILE_BYTES,
+            enable_content_similarity: true,
+            enable_copies: false,
+            cache_size: DEFAULT_CACHE_SIZE,
+        }
+    }
+}
+
 pub struct DagCopyTrace {
     /* Input */
     /// Resolve commit ids to trees in batch.
@@ -42,6 +113,32 @@ pub struct DagCopyTrace {
 
     /// Commit graph algorithms
     dag: Arc<dyn DagAlgorithm + Send + Sync>,
+
+    /// Tunables for rename/copy detection.
+    config: CopyTraceConfig,
+
+    /* Caches, invalidated never -- `DagCopyTrace` is expected to be
+     * short-lived (the span of one or a few `trace_rename` calls). Each
+     * cache holds at most `config.cache_size` entries; beyond that the
+     * least-recently-used entry is evicted. */
+    /// Caches `vertex_to_tree_manifest` results, since backward/forward
+    /// walks repeatedly resolve the same commit's tree.
+    manifest_cache: Mutex<LruCache<dag::Vertex, TreeManifest>>,
+
+    /// Caches the raw (backward-direction) rename map between adjacent
+    /// commits, keyed by `(parent, child)`, since `find_renames_in_direction`
+    /// can otherwise re-diff the same manifest pair many times.
+    rename_cache: Mutex<LruCache<(dag::Vertex, dag::Vertex), HashMap<RepoPathBuf, RepoPathBuf>>>,
+
+    /* Profiling counters, surfaced as trace event fields so a subscriber can break
+     * down where a trace spent its time: walking the commit graph, diffing
+     * trees, or reading file content. */
+    /// Number of tree manifests actually resolved from the store (cache
+    /// misses in `manifest_cache`).
+    trees_fetched: AtomicU64,
+
+    /// Number of files whose content was read for similarity comparison.
+    files_compared: AtomicU64,
 }
 
 impl DagCopyTrace {
@@ -52,15 +149,41 @@ impl DagCopyTrace {
         file_reader: Arc<dyn ReadFileContents<Error = anyhow::Error> + Send + Sync>,
         dag: Arc<dyn DagAlgorithm + Send + Sync>,
     ) -> Result<Self> {
+        Self::new_with_config(
+            root_tree_reader,
+            tree_store,
+            file_reader,
+            dag,
+            CopyTraceConfig::default(),
+        )
+    }
+
+    /// Like `new`, but with explicit `CopyTraceConfig` tunables (thresholds,
+    /// candidate limits, and whether copy detection is enabled).
+    #[allow(dead_code)]
+    pub fn new_with_config(
+        root_tree_reader: Arc<dyn ReadRootTreeIds + Send + Sync>,
+        tree_store: Arc<dyn TreeStore + Send + Sync>,
+        file_reader: Arc<dyn ReadFileContents<Error = anyhow::Error> + Send + Sync>,
+        dag: Arc<dyn DagAlgorithm + Send + Sync>,
+        config: CopyTraceConfig,
+    ) -> Result<Self> {
+        let cache_size = NonZeroUsize::new(config.cache_size.max(1)).expect("clamped to >= 1");
         let dag_copy_trace = Self {
             root_tree_reader,
             tree_store,
             file_reader,
             dag,
+            config,
+            manifest_cache: Mutex::new(LruCache::new(cache_size)),
+            rename_cache: Mutex::new(LruCache::new(cache_size)),
+            trees_fetched: AtomicU64::new(0),
+            files_compared: AtomicU64::new(0),
         };
         Ok(dag_copy_trace)
     }
 
+    #[tracing::instrument(skip(self, keys), fields(keys_len = keys.len()))]
     async fn read_renamed_metadata(
         &self,
         keys: Vec<Key>,
@@ -80,6 +203,10 @@ impl DagCopyTrace {
     }
 
     async fn vertex_to_tree_manifest(&self, commit: &dag::Vertex) -> Result<TreeManifest> {
+        if let Some(tree) = self.manifest_cache.lock().get(commit) {
+            return Ok(tree.clone());
+        }
+
         let commit_id = HgId::from_slice(commit.as_ref())?;
         let commit_to_tree_id = self
             .root_tree_reader
@@ -89,9 +216,14 @@ impl DagCopyTrace {
             return Err(CopyTraceError::RootTreeIdNotFound(commit_id).into());
         }
         let (_, tree_id) = commit_to_tree_id[0];
-        Ok(TreeManifest::durable(self.tree_store.clone(), tree_id))
+        let tree = TreeManifest::durable(self.tree_store.clone(), tree_id);
+        self.trees_fetched.fetch_add(1, Ordering::Relaxed);
+
+        self.manifest_cache.lock().put(commit.clone(), tree.clone());
+        Ok(tree)
     }
 
+    #[tracing::instrument(skip(self), fields(?src, ?dst, ?path))]
     async fn trace_rename_commit(
         &self,
         src: dag::Vertex,
@@ -110,32 +242,118 @@ impl DagCopyTrace {
         Ok(rename_commit)
     }
 
+    /// Resolves the raw (backward-direction: new path -> old path) rename
+    /// map between `parent` and `child`, consulting and populating
+    /// `rename_cache` so that a trace spanning many commits does not re-diff
+    /// an adjacent commit pair whose result is still cached.
+    async fn renames_between(
+        &self,
+        parent: &dag::Vertex,
+        child: &dag::Vertex,
+    ) -> Result<HashMap<RepoPathBuf, RepoPathBuf>> {
+        let cache_key = (parent.clone(), child.clone());
+        if let Some(renames) = self.rename_cache.lock().get(&cache_key) {
+            return Ok(renames.clone());
+        }
+
+        let old_manifest = self.vertex_to_tree_manifest(parent).await?;
+        let new_manifest = self.vertex_to_tree_manifest(child).await?;
+        let renames = self.find_renames(&old_manifest, &new_manifest).await?;
+
+        self.rename_cache.lock().put(cache_key, renames.clone());
+        Ok(renames)
+    }
+
+    /// Paths that differ (added, removed, or changed content) between
+    /// `parent` and `commit` -- everything a diff between their trees would
+    /// touch.
+    async fn changed_paths(
+        &self,
+        parent: &dag::Vertex,
+        commit: &dag::Vertex,
+    ) -> Result<HashSet<RepoPathBuf>> {
+        let old_manifest = self.vertex_to_tree_manifest(parent).await?;
+        let new_manifest = self.vertex_to_tree_manifest(commit).await?;
+        let matcher = AlwaysMatcher::new();
+        let diff = Diff::new(&old_manifest, &new_manifest, &matcher)?;
+        let mut changed = HashSet::new();
+        for entry in diff {
+            changed.insert(entry?.path);
+        }
+        Ok(changed)
+    }
+
+    /// Looks for a rename of `curr_path` across the diff between `commit`
+    /// and each of its parents, returning the resolved path together with
+    /// the commit the walk should continue from. For an ordinary commit
+    /// there's only one parent to check. For a merge, different parents can
+    /// disagree on what `curr_path` used to be -- we evaluate every parent
+    /// and prefer the one whose diff actually mentions `curr_path`, but only
+    /// after discarding matches whose destination path is merely inherited
+    /// unchanged from one of the *other* parents (see the `changed_paths`
+    /// filtering below), so a branch that didn't touch the path at all
+    /// can't be mistaken for the one that introduced (or removed) it.
     async fn find_renames_in_direction(
         &self,
         commit: dag::Vertex,
+        curr_path: &RepoPathBuf,
         direction: SearchDirection,
-    ) -> Result<(HashMap<RepoPathBuf, RepoPathBuf>, dag::Vertex)> {
+    ) -> Result<Option<(RepoPathBuf, dag::Vertex)>> {
         let parents = self.dag.parent_names(commit.clone()).await?;
         if parents.is_empty() {
             return Err(CopyTraceError::NoParents(commit).into());
         }
-        // For simplicity, we only check p1.
-        let p1 = &parents[0];
-        let old_manifest = self.vertex_to_tree_manifest(p1).await?;
-        let new_manifest = self.vertex_to_tree_manifest(&commit).await?;
-        let renames = self.find_renames(&old_manifest, &new_manifest).await?;
-        let (renames, next_commit) = match direction {
-            SearchDirection::Backward => (renames, p1.clone()),
-            SearchDirection::Forward => {
-                let renames = renames
-                    .into_iter()
-                    .map(|(k, v)| (v, k))
-                    .sorted()
-                    .collect::<HashMap<_, _>>();
-                (renames, commit)
+
+        // For a merge commit, `renames_between(parent, commit)` diffs the
+        // merge tree against a single parent, so a file inherited unchanged
+        // from a *different* parent looks like a spurious addition there
+        // and can coincidentally match an unrelated deletion on this
+        // parent's side. Precompute, per parent, the set of paths that
+        // actually differ against `commit`, so such spurious matches can be
+        // filtered out below.
+        let mut changed_paths_by_parent = HashMap::new();
+        if parents.len() > 1 {
+            for other in &parents {
+                let changed = self.changed_paths(other, &commit).await?;
+                changed_paths_by_parent.insert(other.clone(), changed);
             }
-        };
-        Ok((renames, next_commit))
+        }
+
+        for parent in &parents {
+            let mut renames = self.renames_between(parent, &commit).await?;
+
+            if parents.len() > 1 {
+                // Only trust a rename whose destination path also actually
+                // changed relative to every *other* parent -- if it's
+                // unchanged there, it was simply inherited from that
+                // parent, not introduced via `parent`.
+                renames.retain(|child_path, _| {
+                    parents.iter().filter(|other| *other != parent).all(|other| {
+                        changed_paths_by_parent
+                            .get(other)
+                            .map_or(true, |changed| changed.contains(child_path))
+                    })
+                });
+            }
+
+            let (renames, next_commit) = match direction {
+                SearchDirection::Backward => (renames, parent.clone()),
+                SearchDirection::Forward => {
+                    let renames = renames
+                        .into_iter()
+                        .map(|(k, v)| (v, k))
+                        .sorted()
+                        .collect::<HashMap<_, _>>();
+                    (renames, commit.clone())
+                }
+            };
+
+            if let Some(next_path) = renames.get(curr_path) {
+                return Ok(Some((next_path.clone(), next_commit)));
+            }
+        }
+
+        Ok(None)
     }
 
     async fn check_path(
@@ -150,10 +368,250 @@ impl DagCopyTrace {
             Ok(None)
         }
     }
+
+    /// Like `find_renames`, but matches `RightOnly` additions against files
+    /// that survive unchanged in both `old_tree` and `new_tree`, rather than
+    /// only against files that disappeared. This is what lets callers learn
+    /// that a path was copied from another path that is still present at
+    /// `new_tree`. Opt-in via `config.enable_copies` since it requires
+    /// walking every surviving file.
+    async fn find_copies(
+        &self,
+        old_tree: &TreeManifest,
+        new_tree: &TreeManifest,
+    ) -> Result<HashMap<RepoPathBuf, RepoPathBuf>> {
+        if !self.config.enable_copies {
+            return Ok(HashMap::new());
+        }
+
+        let matcher = AlwaysMatcher::new();
+        let (additions, changed_paths) = {
+            let mut additions = Vec::new();
+            let mut changed_paths: HashSet<RepoPathBuf> = HashSet::new();
+
+            let diff = Diff::new(old_tree, new_tree, &matcher)?;
+            for entry in diff {
+                let entry = entry?;
+                if let DiffType::RightOnly(file_metadata) = &entry.diff_type {
+                    additions.push(Key {
+                        path: entry.path.clone(),
+                        hgid: file_metadata.hgid,
+                    });
+                }
+                changed_paths.insert(entry.path);
+            }
+
+            (additions, changed_paths)
+        };
+        if additions.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        // Survivors: files present in `new_tree` that weren't touched by the
+        // diff, i.e. unchanged between `old_tree` and `new_tree`. Exact
+        // blob-id matches are always looked for; if pairing survivors with
+        // `additions` exceeds `max_rename_candidates`, `find_similar_pairs`
+        // logs a warning and skips the similarity pass.
+        let mut survivors = Vec::new();
+        for file in new_tree.files(&matcher) {
+            let file = file?;
+            if !changed_paths.contains(&file.path) {
+                survivors.push(Key {
+                    path: file.path,
+                    hgid: file.meta.hgid,
+                });
+            }
+        }
+        if survivors.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        // Exact matches first: bucket survivors by blob id.
+        let mut survivors_by_hgid: HashMap<HgId, RepoPathBuf> = HashMap::new();
+        for survivor in &survivors {
+            survivors_by_hgid
+                .entry(survivor.hgid)
+                .or_insert_with(|| survivor.path.clone());
+        }
+
+        let mut copies = HashMap::new();
+        let mut unmatched_additions = Vec::new();
+        for addition in additions {
+            match survivors_by_hgid.get(&addition.hgid) {
+                Some(from_path) => {
+                    copies.insert(addition.path, from_path.clone());
+                }
+                None => unmatched_additions.push(addition),
+            }
+        }
+
+        // Similarity pass, reusing the same scoring/threshold as renames.
+        if !unmatched_additions.is_empty() {
+            let similarity_copies = self.find_similar_pairs(unmatched_additions, survivors).await?;
+            copies.extend(similarity_copies);
+        }
+
+        Ok(copies)
+    }
+
+    /// Resolve where `dst_path` (as of `dst`) was copied from, if it was
+    /// copied (as opposed to renamed) from a file that still exists at
+    /// `dst`. Returns the source path together with the commit (`src`) it
+    /// was read against. No-op unless `config.enable_copies` was set via
+    /// `DagCopyTrace::new_with_config`.
+    pub async fn trace_copy(
+        &self,
+        src: dag::Vertex,
+        dst: dag::Vertex,
+        dst_path: RepoPathBuf,
+    ) -> Result<Option<(RepoPathBuf, dag::Vertex)>> {
+        if !self.config.enable_copies {
+            return Ok(None);
+        }
+
+        let old_manifest = self.vertex_to_tree_manifest(&src).await?;
+        let new_manifest = self.vertex_to_tree_manifest(&dst).await?;
+        let copies = self.find_copies(&old_manifest, &new_manifest).await?;
+        Ok(copies
+            .get(&dst_path)
+            .map(|from_path| (from_path.clone(), src.clone())))
+    }
+
+    /// Greedily pair up `additions` and `deletions` by content-line
+    /// similarity, keeping only pairs scoring at or above
+    /// `config.similarity_threshold`. Guards against the O(n*m) blowup of a
+    /// huge commit by falling back to exact-blob-id matching only (i.e.
+    /// doing nothing here, since that pass already ran) when the candidate
+    /// product exceeds `config.max_rename_candidates`.
+    async fn find_similar_pairs(
+        &self,
+        additions: Vec<Key>,
+        deletions: Vec<Key>,
+    ) -> Result<HashMap<RepoPathBuf, RepoPathBuf>> {
+        if !self.config.enable_content_similarity {
+            return Ok(HashMap::new());
+        }
+
+        let candidates = additions.len().saturating_mul(deletions.len());
+        if candidates > self.config.max_rename_candidates {
+            tracing::warn!(
+                additions = additions.len(),
+                deletions = deletions.len(),
+                candidates,
+                "skipping content-similarity rename detection: too many candidate pairs"
+            );
+            return Ok(HashMap::new());
+        }
+
+        let mut deletion_lines = Vec::with_capacity(deletions.len());
+        for deletion in &deletions {
+            deletion_lines.push(self.read_lines_if_small(deletion.clone()).await?);
+        }
+
+        let mut matched = vec![false; deletions.len()];
+        let mut renames = HashMap::new();
+
+        for addition in additions {
+            let addition_lines = match self.read_lines_if_small(addition.clone()).await? {
+                Some(lines) if !lines.is_empty() => lines,
+                _ => continue,
+            };
+
+            let mut best: Option<(usize, f32)> = None;
+            for (idx, lines) in deletion_lines.iter().enumerate() {
+                if matched[idx] {
+                    continue;
+                }
+                let lines = match lines {
+                    Some(lines) if !lines.is_empty() => lines,
+                    _ => continue,
+                };
+
+                // Skip candidate pairs whose size ratio is wildly off
+                // before doing the more expensive line comparison.
+                let (a, b) = (addition_lines.len(), lines.len());
+                let size_ratio = a.min(b) as f32 / a.max(b) as f32;
+                if size_ratio < self.config.similarity_threshold {
+                    continue;
+                }
+
+                let score = line_similarity(&addition_lines, lines);
+                if score >= self.config.similarity_threshold
+                    && best.map_or(true, |(_, best_score)| score > best_score)
+                {
+                    best = Some((idx, score));
+                }
+            }
+
+            if let Some((idx, score)) = best {
+                matched[idx] = true;
+                tracing::trace!(
+                    from = %deletions[idx].path,
+                    to = %addition.path,
+                    score,
+                    "content-similarity match"
+                );
+                renames.insert(addition.path, deletions[idx].path.clone());
+            }
+        }
+
+        Ok(renames)
+    }
+
+    /// Reads `key`'s content and splits it into lines, unless it's larger
+    /// than `config.max_file_bytes`, in which case it's never line-diffed
+    /// (returns `None` so callers fall back to blob-id-only comparison).
+    ///
+    /// Zero-byte content is treated the same way: `split_lines` on empty
+    /// data yields a single empty line rather than no lines, which would
+    /// otherwise make `line_similarity` score any two distinct empty files
+    /// as a perfect match.
+    async fn read_lines_if_small(&self, key: Key) -> Result<Option<Vec<Vec<u8>>>> {
+        let mut contents = self.file_reader.read_file_contents(vec![key]).await;
+        match contents.next().await {
+            Some(entry) => {
+                let (data, _key) = entry?;
+                self.files_compared.fetch_add(1, Ordering::Relaxed);
+                if data.as_ref().is_empty() || data.as_ref().len() as u64 > self.config.max_file_bytes {
+                    Ok(None)
+                } else {
+                    Ok(Some(split_lines(data.as_ref())))
+                }
+            }
+            None => Ok(Some(Vec::new())),
+        }
+    }
+}
+
+/// Fraction of lines in `a` that also appear in `b` (each line in `b` can
+/// only satisfy one match), normalized by the longer side so that
+/// insertions/deletions of whole lines reduce the score symmetrically.
+fn line_similarity(a: &[Vec<u8>], b: &[Vec<u8>]) -> f32 {
+    let mut available: HashMap<&[u8], usize> = HashMap::new();
+    for line in b {
+        *available.entry(line.as_slice()).or_insert(0) += 1;
+    }
+
+    let mut common = 0usize;
+    for line in a {
+        if let Some(count) = available.get_mut(line.as_slice()) {
+            if *count > 0 {
+                *count -= 1;
+                common += 1;
+            }
+        }
+    }
+
+    common as f32 / a.len().max(b.len()) as f32
+}
+
+fn split_lines(data: &[u8]) -> Vec<Vec<u8>> {
+    data.split(|&b| b == b'\n').map(|line| line.to_vec()).collect()
 }
 
 #[async_trait]
 impl CopyTrace for DagCopyTrace {
+    #[tracing::instrument(skip(self), fields(?src, ?dst, ?src_path))]
     async fn trace_rename(
         &self,
         src: dag::Vertex,
@@ -191,6 +649,7 @@ impl CopyTrace for DagCopyTrace {
         }
     }
 
+    #[tracing::instrument(skip(self), fields(?src, ?dst, ?dst_path))]
     async fn trace_rename_backward(
         &self,
         src: dag::Vertex,
@@ -199,9 +658,18 @@ impl CopyTrace for DagCopyTrace {
     ) -> Result<Option<RepoPathBuf>> {
         tracing::trace!(?src, ?dst, ?dst_path, "trace_rename_backward");
         let (mut curr, target, mut curr_path) = (dst, src, dst_path);
+        let mut iteration: u64 = 0;
 
         loop {
-            tracing::trace!(?curr, ?curr_path, " loop starts");
+            iteration += 1;
+            tracing::trace!(
+                iteration,
+                ?curr,
+                ?curr_path,
+                trees_fetched = self.trees_fetched.load(Ordering::Relaxed),
+                files_compared = self.files_compared.load(Ordering::Relaxed),
+                "loop starts"
+            );
             let rename_commit = match self
                 .trace_rename_commit(target.clone(), curr.clone(), curr_path.clone())
                 .await?
@@ -209,24 +677,28 @@ impl CopyTrace for DagCopyTrace {
                 Some(rename_commit) => rename_commit,
                 None => return self.check_path(&target, curr_path).await,
             };
-            tracing::trace!(?rename_commit, " found");
+            tracing::trace!(?rename_commit, "found");
 
             if rename_commit == target {
                 return Ok(Some(curr_path));
             }
-            let (renames, next_commit) = self
-                .find_renames_in_direction(rename_commit, SearchDirection::Backward)
-                .await?;
-            if let Some(next_path) = renames.get(&curr_path) {
-                curr = next_commit;
-                curr_path = next_path.clone();
-            } else {
-                // no rename info for curr_path
-                return Ok(None);
+            match self
+                .find_renames_in_direction(rename_commit, &curr_path, SearchDirection::Backward)
+                .await?
+            {
+                Some((next_path, next_commit)) => {
+                    curr = next_commit;
+                    curr_path = next_path;
+                }
+                None => {
+                    // no rename info for curr_path on any parent
+                    return Ok(None);
+                }
             }
         }
     }
 
+    #[tracing::instrument(skip(self), fields(?src, ?dst, ?src_path))]
     async fn trace_rename_forward(
         &self,
         src: dag::Vertex,
@@ -235,9 +707,18 @@ impl CopyTrace for DagCopyTrace {
     ) -> Result<Option<RepoPathBuf>> {
         tracing::trace!(?src, ?dst, ?src_path, "trace_rename_forward");
         let (mut curr, target, mut curr_path) = (src, dst, src_path);
+        let mut iteration: u64 = 0;
 
         loop {
-            tracing::trace!(?curr, ?curr_path, " loop starts");
+            iteration += 1;
+            tracing::trace!(
+                iteration,
+                ?curr,
+                ?curr_path,
+                trees_fetched = self.trees_fetched.load(Ordering::Relaxed),
+                files_compared = self.files_compared.load(Ordering::Relaxed),
+                "loop starts"
+            );
             let rename_commit = match self
                 .trace_rename_commit(curr.clone(), target.clone(), curr_path.clone())
                 .await?
@@ -245,56 +726,122 @@ impl CopyTrace for DagCopyTrace {
                 Some(rename_commit) => rename_commit,
                 None => return self.check_path(&target, curr_path).await,
             };
-            tracing::trace!(?rename_commit, " found");
+            tracing::trace!(?rename_commit, "found");
 
             if rename_commit == curr {
                 return Ok(Some(curr_path));
             }
-            let (renames, next_commit) = self
-                .find_renames_in_direction(rename_commit, SearchDirection::Forward)
-                .await?;
-            if let Some(next_path) = renames.get(&curr_path) {
-                curr = next_commit;
-                curr_path = next_path.clone();
-            } else {
-                // no rename info for curr_path
-                return Ok(None);
+            match self
+                .find_renames_in_direction(rename_commit, &curr_path, SearchDirection::Forward)
+                .await?
+            {
+                Some((next_path, next_commit)) => {
+                    curr = next_commit;
+                    curr_path = next_path;
+                }
+                None => {
+                    // no rename info for curr_path on any parent
+                    return Ok(None);
+                }
             }
         }
     }
 
+    #[tracing::instrument(skip(self, old_tree, new_tree))]
     async fn find_renames(
         &self,
         old_tree: &TreeManifest,
         new_tree: &TreeManifest,
     ) -> Result<HashMap<RepoPathBuf, RepoPathBuf>> {
-        // todo:
         // * [x] parse file header and get mv info
-        // * support content similarity for sl repo
-        // * support content similarity for git repo
-        let mut new_files = Vec::new();
-
-        {
+        // * [x] support content similarity for sl repo / git repo
+        let (deletions, additions) = {
             // this block is for dropping matcher and diff at the end of the block,
             // otherwise the compiler compilains variable might be used across 'await'
 
+            let mut deletions = Vec::new();
+            let mut additions = Vec::new();
+
             let matcher = AlwaysMatcher::new();
             let diff = Diff::new(old_tree, new_tree, &matcher)?;
             for entry in diff {
                 let entry = entry?;
 
-                if let DiffType::RightOnly(file_metadata) = entry.diff_type {
-                    let path = entry.path;
-                    let key = Key {
-                        path,
-                        hgid: file_metadata.hgid,
-                    };
-                    new_files.push(key);
+                match entry.diff_type {
+                    DiffType::LeftOnly(file_metadata) => {
+                        deletions.push(Key {
+                            path: entry.path,
+                            hgid: file_metadata.hgid,
+                        });
+                    }
+                    DiffType::RightOnly(file_metadata) => {
+                        additions.push(Key {
+                            path: entry.path,
+                            hgid: file_metadata.hgid,
+                        });
+                    }
+                    DiffType::Changed(..) => {}
                 }
             }
+
+            (deletions, additions)
+        };
+
+        let mut renames = self.read_renamed_metadata(additions.clone()).await?;
+
+        // Exact matches: bucket deletions by blob id, pair any addition
+        // whose blob id equals a deletion's. Zero content reads needed.
+        let mut deletions_by_hgid: HashMap<HgId, Vec<RepoPathBuf>> = HashMap::new();
+        for deletion in &deletions {
+            deletions_by_hgid
+                .entry(deletion.hgid)
+                .or_default()
+                .push(deletion.path.clone());
         }
 
-        self.read_renamed_metadata(new_files).await
+        let mut matched_deletions: HashSet<RepoPathBuf> = HashSet::new();
+        let mut unmatched_additions = Vec::new();
+        for addition in additions {
+            if renames.contains_key(&addition.path) {
+                // Already resolved via rename metadata.
+                continue;
+            }
+            let exact_match = deletions_by_hgid.get_mut(&addition.hgid).and_then(|paths| {
+                let pos = paths
+                    .iter()
+                    .position(|path| !matched_deletions.contains(path))?;
+                Some(paths.swap_remove(pos))
+            });
+            match exact_match {
+                Some(from_path) => {
+                    matched_deletions.insert(from_path.clone());
+                    renames.insert(addition.path, from_path);
+                }
+                None => unmatched_additions.push(addition),
+            }
+        }
+
+        let unmatched_deletions: Vec<Key> = deletions
+            .into_iter()
+            .filter(|deletion| !matched_deletions.contains(&deletion.path))
+            .collect();
+
+        // Similarity pass: whatever is left needs actual content reads.
+        if !unmatched_additions.is_empty() && !unmatched_deletions.is_empty() {
+            let similarity_renames = self
+                .find_similar_pairs(unmatched_additions, unmatched_deletions)
+                .await?;
+            renames.extend(similarity_renames);
+        }
+
+        tracing::trace!(
+            renames_found = renames.len(),
+            trees_fetched = self.trees_fetched.load(Ordering::Relaxed),
+            files_compared = self.files_compared.load(Ordering::Relaxed),
+            "find_renames finished"
+        );
+
+        Ok(renames)
     }
 }
 
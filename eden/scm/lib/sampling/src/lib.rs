@@ -6,25 +6,71 @@
  */
 
 use std::collections::HashMap;
+use std::fmt;
 use std::fs::File;
 use std::fs::OpenOptions;
+use std::io;
+use std::io::Write as _;
+use std::path::Path;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
 
 use once_cell::sync::OnceCell;
 use parking_lot::Mutex;
-use parking_lot::MutexGuard;
+use rand::Rng;
 
 pub static CONFIG: OnceCell<Option<Arc<SamplingConfig>>> = OnceCell::new();
 
+const DEFAULT_FLUSH_BYTES: usize = 64 * 1024;
+const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_MAX_BACKUPS: u32 = 3;
+
 pub fn init(config: &dyn configmodel::Config) {
     CONFIG.get_or_init(|| SamplingConfig::new(config).map(Arc::new));
 }
 
+/// Flushes any buffered-but-unwritten samples held by `CONFIG`'s sink to
+/// disk. `CONFIG` is a process-global `static`, and Rust does not run
+/// destructors for statics at normal process exit, so `BufferedFileSink`'s
+/// `Drop` impl will not fire for a short-lived command that just returns
+/// from `main`. Callers must invoke this explicitly during command
+/// teardown to avoid losing buffered samples. No-op if sampling isn't
+/// configured or `init` was never called.
+pub fn flush() -> io::Result<()> {
+    if let Some(Some(config)) = CONFIG.get() {
+        config.flush()?;
+    }
+    Ok(())
+}
+
+// Per-key sampling cap state: how many matching records have been written
+// so far. Once `seen` reaches the key's `sampling.maxsamples.<key>`, every
+// later record for that key is dropped. Records are written to the sink
+// immediately and can't be retracted, so this is a hard stop rather than a
+// true reservoir (which would require holding candidates back and only
+// flushing a fixed-size sample at the end).
+#[derive(Debug, Default)]
+struct SampleCapState {
+    seen: u64,
+}
+
+/// A destination for sampled records. `SamplingConfig` writes to whatever
+/// `SampleSink` it was constructed with, which lets the on-disk format
+/// (buffering, rotation, ...) vary independently of the sampling logic.
+pub trait SampleSink: fmt::Debug + Send + Sync {
+    fn write(&self, data: &[u8]) -> io::Result<()>;
+    fn flush(&self) -> io::Result<()>;
+}
+
 #[derive(Debug)]
 pub struct SamplingConfig {
     keys: HashMap<String, String>,
-    file: Mutex<File>,
+    rates: HashMap<String, f64>,
+    max_samples: HashMap<String, u64>,
+    sample_caps: Mutex<HashMap<String, SampleCapState>>,
+    sink: Arc<dyn SampleSink>,
 }
 
 impl SamplingConfig {
@@ -45,6 +91,28 @@ impl SamplingConfig {
             return None;
         }
 
+        let rates: HashMap<String, f64> = config
+            .keys("sampling")
+            .into_iter()
+            .filter_map(|name| {
+                let key = name.strip_prefix("rate.")?;
+                let val = config.get("sampling", &name)?;
+                let rate = parse_rate(&val)?;
+                Some((key.to_string(), rate))
+            })
+            .collect();
+
+        let max_samples: HashMap<String, u64> = config
+            .keys("sampling")
+            .into_iter()
+            .filter_map(|name| {
+                let key = name.strip_prefix("maxsamples.")?;
+                let val = config.get("sampling", &name)?;
+                let max: u64 = val.parse().ok()?;
+                Some((key.to_string(), max))
+            })
+            .collect();
+
         if let Some((output_file, okay_exists)) = sampling_output_file(config) {
             match OpenOptions::new()
                 .create(okay_exists)
@@ -53,9 +121,15 @@ impl SamplingConfig {
                 .open(&output_file)
             {
                 Ok(file) => {
+                    let sink: Arc<dyn SampleSink> =
+                        Arc::new(BufferedFileSink::new(file, output_file.clone(), config));
+
                     return Some(Self {
                         keys: sample_categories,
-                        file: Mutex::new(file),
+                        rates,
+                        max_samples,
+                        sample_caps: Mutex::new(HashMap::new()),
+                        sink,
                     });
                 }
                 Err(err) => {
@@ -76,8 +150,72 @@ impl SamplingConfig {
         self.keys.get(key).map(|c| &**c)
     }
 
-    pub fn file(&self) -> MutexGuard<File> {
-        self.file.lock()
+    /// Returns whether a record for `key` should be written, applying
+    /// `sampling.rate.<key>` and/or `sampling.maxsamples.<key>` if
+    /// configured. Keys with no rate or cap are always sampled.
+    pub fn should_sample(&self, key: &str) -> bool {
+        if let Some(&rate) = self.rates.get(key) {
+            if !should_sample_rate(rate) {
+                return false;
+            }
+        }
+
+        if let Some(&max) = self.max_samples.get(key) {
+            if !self.should_sample_capped(key, max) {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    fn should_sample_capped(&self, key: &str, max_samples: u64) -> bool {
+        let mut sample_caps = self.sample_caps.lock();
+        let state = sample_caps.entry(key.to_string()).or_default();
+        if state.seen >= max_samples {
+            return false;
+        }
+        state.seen += 1;
+        true
+    }
+
+    /// Thin adapter over the configured `SampleSink` so existing call sites
+    /// that do `config.file().write_all(...)` keep compiling. Writes now go
+    /// through the sink's buffering/rotation instead of hitting disk directly.
+    pub fn file(&self) -> SinkWriter<'_> {
+        SinkWriter {
+            sink: &*self.sink,
+        }
+    }
+
+    /// Flushes any buffered-but-unwritten samples to the sink.
+    pub fn flush(&self) -> io::Result<()> {
+        self.sink.flush()
+    }
+}
+
+fn should_sample_rate(rate: f64) -> bool {
+    if rate >= 1.0 {
+        true
+    } else if rate <= 0.0 {
+        false
+    } else {
+        rand::thread_rng().gen_bool(rate)
+    }
+}
+
+// Parses a `sampling.rate.<key>` value. Accepts a float in `[0, 1]`
+// (the fraction of matching events to keep) or an integer `N` meaning
+// "1 in N" (equivalent to a rate of `1/N`).
+fn parse_rate(val: &str) -> Option<f64> {
+    let val = val.trim();
+    let num: f64 = val.parse().ok()?;
+    if num < 0.0 {
+        None
+    } else if num <= 1.0 {
+        Some(num)
+    } else {
+        Some(1.0 / num)
     }
 }
 
@@ -100,3 +238,176 @@ fn sampling_output_file(config: &dyn configmodel::Config) -> Option<(PathBuf, bo
         .into_iter()
         .find(|(path, _okay_exists)| path.parent().map_or(false, |d| d.exists()))
 }
+
+/// Adapts a `&dyn SampleSink` to `std::io::Write` so `SamplingConfig::file()`
+/// can keep returning something callers write bytes into directly.
+pub struct SinkWriter<'a> {
+    sink: &'a dyn SampleSink,
+}
+
+impl<'a> io::Write for SinkWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sink.write(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sink.flush()
+    }
+}
+
+struct BufferedFileState {
+    file: File,
+    path: PathBuf,
+    buf: Vec<u8>,
+    file_size: u64,
+    last_flush: Instant,
+}
+
+/// A `SampleSink` that batches writes in memory and only touches disk once
+/// `sampling.flushbytes` have accumulated or `sampling.flushinterval` has
+/// elapsed since the last flush. If `sampling.maxfilesize` is set, the file
+/// is rotated to `<path>.1`, `<path>.2`, ... (keeping `sampling.maxbackups`
+/// generations) once it grows past that size.
+struct BufferedFileSink {
+    state: Mutex<BufferedFileState>,
+    flush_bytes: usize,
+    flush_interval: Duration,
+    max_file_size: Option<u64>,
+    max_backups: u32,
+}
+
+impl fmt::Debug for BufferedFileSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BufferedFileSink")
+            .field("flush_bytes", &self.flush_bytes)
+            .field("flush_interval", &self.flush_interval)
+            .field("max_file_size", &self.max_file_size)
+            .field("max_backups", &self.max_backups)
+            .finish()
+    }
+}
+
+impl BufferedFileSink {
+    fn new(file: File, path: PathBuf, config: &dyn configmodel::Config) -> Self {
+        let file_size = file.metadata().map(|m| m.len()).unwrap_or(0);
+
+        let flush_bytes = config
+            .get("sampling", "flushbytes")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_FLUSH_BYTES);
+        let flush_interval = config
+            .get("sampling", "flushinterval")
+            .and_then(|v| v.parse::<f64>().ok())
+            .map(Duration::from_secs_f64)
+            .unwrap_or(DEFAULT_FLUSH_INTERVAL);
+        let max_file_size = config
+            .get("sampling", "maxfilesize")
+            .and_then(|v| v.parse().ok());
+        let max_backups = config
+            .get("sampling", "maxbackups")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_MAX_BACKUPS);
+
+        Self {
+            state: Mutex::new(BufferedFileState {
+                file,
+                path,
+                buf: Vec::new(),
+                file_size,
+                last_flush: Instant::now(),
+            }),
+            flush_bytes,
+            flush_interval,
+            max_file_size,
+            max_backups,
+        }
+    }
+
+    fn flush_locked(&self, state: &mut BufferedFileState) -> io::Result<()> {
+        if !state.buf.is_empty() {
+            state.file.write_all(&state.buf)?;
+            state.file_size += state.buf.len() as u64;
+            state.buf.clear();
+        }
+        state.file.flush()?;
+        state.last_flush = Instant::now();
+
+        if let Some(max_file_size) = self.max_file_size {
+            if state.file_size >= max_file_size {
+                self.rotate_locked(state)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn rotate_locked(&self, state: &mut BufferedFileState) -> io::Result<()> {
+        if self.max_backups == 0 {
+            // Nothing to rotate into; just truncate in place.
+            state.file = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(&state.path)?;
+            state.file_size = 0;
+            return Ok(());
+        }
+
+        for gen in (1..self.max_backups).rev() {
+            let src = backup_path(&state.path, gen);
+            let dst = backup_path(&state.path, gen + 1);
+            if src.exists() {
+                let _ = std::fs::rename(&src, &dst);
+            }
+        }
+        let _ = std::fs::rename(&state.path, backup_path(&state.path, 1));
+
+        state.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&state.path)?;
+        state.file_size = 0;
+
+        Ok(())
+    }
+}
+
+impl SampleSink for BufferedFileSink {
+    fn write(&self, data: &[u8]) -> io::Result<()> {
+        let mut state = self.state.lock();
+        state.buf.extend_from_slice(data);
+
+        let should_flush =
+            state.buf.len() >= self.flush_bytes || state.last_flush.elapsed() >= self.flush_interval;
+        if should_flush {
+            self.flush_locked(&mut state)?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        let mut state = self.state.lock();
+        self.flush_locked(&mut state)
+    }
+}
+
+impl Drop for BufferedFileSink {
+    fn drop(&mut self) {
+        // Best-effort: catches cases where the sink is actually dropped,
+        // e.g. a scoped `SamplingConfig` going out of scope. This does NOT
+        // cover the common "short-lived command exits" case when the sink
+        // is reached through the `CONFIG` static, since Rust doesn't run
+        // destructors for statics at normal process exit -- callers must
+        // call `sampling::flush()` explicitly during teardown for that.
+        let mut state = self.state.lock();
+        let _ = self.flush_locked(&mut state);
+    }
+}
+
+fn backup_path(path: &Path, generation: u32) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{}", generation));
+    PathBuf::from(name)
+}
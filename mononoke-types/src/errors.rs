@@ -0,0 +1,20 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use failure::Fail;
+
+pub use failure::Error;
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+#[derive(Debug, Fail)]
+pub enum ErrorKind {
+    #[fail(display = "invalid Thrift structure '{}': {}", _0, _1)]
+    InvalidThrift(String, String),
+    #[fail(display = "error while deserializing blob for '{}'", _0)]
+    BlobDeserializeError(String),
+    #[fail(display = "invalid Bonsai changeset: {}", _0)]
+    InvalidBonsaiChangeset(String),
+}
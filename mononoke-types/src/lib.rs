@@ -0,0 +1,18 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+#[cfg(test)]
+#[macro_use]
+extern crate maplit;
+#[cfg(test)]
+#[macro_use]
+extern crate quickcheck;
+
+mod bonsai_changeset;
+mod errors;
+
+pub use crate::bonsai_changeset::{BonsaiChangeset, BonsaiChangesetMut};
+pub use crate::errors::{Error, ErrorKind, Result};